@@ -0,0 +1,53 @@
+// Command-line argument handling. Hand-rolled in the same style as the
+// `--rules` flag introduced earlier, rather than pulling in an argument
+// parsing crate: the flag set is small and stable.
+
+use std::env;
+use std::process;
+
+#[derive(Debug)]
+pub struct Args {
+    pub rules: Option<String>,
+    pub paths: Vec<String>,
+    pub in_place: bool,
+    pub output: Option<String>,
+    pub check: bool,
+}
+
+// Reports a CLI usage error and exits, without panicking: this is something
+// the user did wrong, not a bug, so it shouldn't come with a backtrace.
+fn usage() -> ! {
+    eprintln!("usage: supercollapser [--rules <file>] [-i | -o <file> | --check] <manifest>... (use - for stdin)");
+    process::exit(2);
+}
+
+pub fn parse() -> Args {
+    let mut rules = None;
+    let mut paths = Vec::new();
+    let mut in_place = false;
+    let mut output = None;
+    let mut check = false;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--rules" => rules = Some(args.next().unwrap_or_else(|| usage())),
+            "-i" | "--in-place" => in_place = true,
+            "-o" | "--output" => output = Some(args.next().unwrap_or_else(|| usage())),
+            "--check" => check = true,
+            _ => paths.push(arg),
+        }
+    }
+
+    if paths.is_empty() {
+        paths.push(String::from("-"));
+    }
+    if in_place && output.is_some() {
+        usage();
+    }
+    if in_place && paths.iter().any(|p| p == "-") {
+        usage();
+    }
+
+    Args { rules, paths, in_place, output, check }
+}
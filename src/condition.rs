@@ -0,0 +1,290 @@
+// A small boolean expression subsystem for WPT-style `if` condition text,
+// e.g.
+//   (os == "win") and (version == "6.1.7601") and not webrender and e10s
+//   (os == "win") and (e10s or stylo)
+//   not (os == "android" or os == "ios")
+// This replaces naive `" and "`-splitting with a real lexer/parser that
+// understands `or`, `not` and nested parentheses, and a conversion to
+// disjunctive normal form (DNF) so every OR-branch becomes its own tokenset
+// that the existing collapser can reason about independently.
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Eq,
+    Neq,
+    Word(String),
+}
+
+fn lex(text: &str) -> Vec<Token> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '(' {
+            tokens.push(Token::LParen);
+            i += 1;
+        } else if c == ')' {
+            tokens.push(Token::RParen);
+            i += 1;
+        } else if c == '=' && chars.get(i + 1) == Some(&'=') {
+            tokens.push(Token::Eq);
+            i += 2;
+        } else if c == '!' && chars.get(i + 1) == Some(&'=') {
+            tokens.push(Token::Neq);
+            i += 2;
+        } else if c == '"' {
+            let start = i;
+            i += 1;
+            while i < chars.len() && chars[i] != '"' {
+                i += 1;
+            }
+            i += 1; // consume closing quote (or run off the end of a malformed string)
+            let word: String = chars[start..i].iter().collect();
+            tokens.push(Token::Word(word));
+        } else {
+            let start = i;
+            while i < chars.len() && !chars[i].is_whitespace()
+                && chars[i] != '(' && chars[i] != ')'
+                && chars[i] != '"' && chars[i] != '=' && chars[i] != '!' {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            match word.as_str() {
+                "and" => tokens.push(Token::And),
+                "or" => tokens.push(Token::Or),
+                "not" => tokens.push(Token::Not),
+                _ => tokens.push(Token::Word(word)),
+            }
+        }
+    }
+    tokens
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Comparison(String),
+    Ident(String),
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let tok = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        tok
+    }
+
+    // expr := and_expr ( 'or' and_expr )*
+    fn parse_expr(&mut self) -> Expr {
+        let mut terms = vec![self.parse_and()];
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            terms.push(self.parse_and());
+        }
+        if terms.len() == 1 {
+            terms.pop().unwrap()
+        } else {
+            Expr::Or(terms)
+        }
+    }
+
+    // and_expr := not_expr ( 'and' not_expr )*
+    fn parse_and(&mut self) -> Expr {
+        let mut terms = vec![self.parse_not()];
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            terms.push(self.parse_not());
+        }
+        if terms.len() == 1 {
+            terms.pop().unwrap()
+        } else {
+            Expr::And(terms)
+        }
+    }
+
+    // not_expr := 'not' not_expr | primary
+    fn parse_not(&mut self) -> Expr {
+        if self.peek() == Some(&Token::Not) {
+            self.next();
+            Expr::Not(Box::new(self.parse_not()))
+        } else {
+            self.parse_primary()
+        }
+    }
+
+    // primary := '(' expr ')' | comparison | ident
+    fn parse_primary(&mut self) -> Expr {
+        match self.next().expect("unexpected end of condition") {
+            Token::LParen => {
+                let inner = self.parse_expr();
+                assert_eq!(self.next(), Some(Token::RParen), "expected closing parenthesis");
+                inner
+            }
+            Token::Word(lhs) => {
+                match self.peek() {
+                    Some(&Token::Eq) | Some(&Token::Neq) => {
+                        let op = if self.next() == Some(Token::Eq) { "==" } else { "!=" };
+                        let rhs = match self.next().expect("expected comparison value") {
+                            Token::Word(w) => w,
+                            other => panic!("expected comparison value, found {:?}", other),
+                        };
+                        Expr::Comparison(format!("({} {} {})", lhs, op, rhs))
+                    }
+                    _ => Expr::Ident(lhs),
+                }
+            }
+            other => panic!("unexpected token {:?}", other),
+        }
+    }
+}
+
+fn parse(text: &str) -> Expr {
+    let mut parser = Parser { tokens: lex(text), pos: 0 };
+    let expr = parser.parse_expr();
+    assert!(parser.pos == parser.tokens.len(), "trailing tokens in condition: {}", text);
+    expr
+}
+
+// Negates a rendered literal token, e.g. "webrender" <-> "not webrender".
+// This is the canonical notion of negation the collapser works with: tokens
+// are opaque strings, and flipping one twice gives back the original.
+pub fn flip(token: &str) -> String {
+    if token.find("not ") == Some(0) {
+        token[4..].to_string()
+    } else {
+        "not ".to_string() + token
+    }
+}
+
+// Pushes `not` down to the leaves (De Morgan's laws), so that the only
+// negations left in the tree wrap a single Comparison or Ident.
+fn to_nnf(expr: Expr, negated: bool) -> Expr {
+    match expr {
+        Expr::Not(inner) => to_nnf(*inner, !negated),
+        Expr::And(terms) => {
+            let terms = terms.into_iter().map(|t| to_nnf(t, negated)).collect();
+            if negated { Expr::Or(terms) } else { Expr::And(terms) }
+        }
+        Expr::Or(terms) => {
+            let terms = terms.into_iter().map(|t| to_nnf(t, negated)).collect();
+            if negated { Expr::And(terms) } else { Expr::Or(terms) }
+        }
+        leaf => if negated { Expr::Not(Box::new(leaf)) } else { leaf },
+    }
+}
+
+// Distributes And over Or to produce a list of conjunctions (each a Vec of
+// literal tokens) whose disjunction is equivalent to `expr`. `expr` must
+// already be in negation normal form.
+fn dnf_terms(expr: &Expr) -> Vec<Vec<String>> {
+    match expr {
+        Expr::Comparison(s) => vec![vec![s.clone()]],
+        Expr::Ident(s) => vec![vec![s.clone()]],
+        Expr::Not(inner) => {
+            let literal = match inner.as_ref() {
+                Expr::Comparison(s) => s.clone(),
+                Expr::Ident(s) => s.clone(),
+                other => panic!("not in negation normal form: {:?}", other),
+            };
+            vec![vec![flip(&literal)]]
+        }
+        Expr::Or(terms) => terms.iter().flat_map(dnf_terms).collect(),
+        Expr::And(terms) => {
+            let mut product = vec![Vec::new()];
+            for term in terms {
+                let mut next = Vec::new();
+                for prefix in &product {
+                    for branch in dnf_terms(term) {
+                        let mut combined = prefix.clone();
+                        combined.extend(branch);
+                        next.push(combined);
+                    }
+                }
+                product = next;
+            }
+            product
+        }
+    }
+}
+
+// Parses `text` as a boolean condition and converts it to DNF, returning one
+// tokenset per OR-branch. This is what main() calls in place of the old
+// `" and "`-split: a condition with no `or` in it, and no `not` applied to
+// a parenthesised `and`, just comes back as a single tokenset, same as
+// before. `not (a and b)` becomes `not a or not b`, i.e. two tokensets.
+pub fn condition_to_tokensets(text: &str) -> Vec<Vec<String>> {
+    let expr = to_nnf(parse(text), false);
+    dnf_terms(&expr)
+}
+
+// Re-renders a tokenset (a conjunction of literals) back into `and`-joined
+// condition text, the inverse of `condition_to_tokensets` for a single
+// branch.
+pub fn tokenset_to_condition(tokenset: &[String]) -> String {
+    tokenset.join(" and ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokenset(literals: Vec<&str>) -> Vec<String> {
+        literals.into_iter().map(String::from).collect()
+    }
+
+    #[test]
+    fn simple_and_is_a_single_tokenset() {
+        let result = condition_to_tokensets("(os == \"win\") and e10s");
+        assert_eq!(result, vec![tokenset(vec!["(os == \"win\")", "e10s"])]);
+    }
+
+    #[test]
+    fn or_produces_one_tokenset_per_branch() {
+        let result = condition_to_tokensets("(os == \"win\") and (e10s or stylo)");
+        assert_eq!(result, vec![
+            tokenset(vec!["(os == \"win\")", "e10s"]),
+            tokenset(vec!["(os == \"win\")", "stylo"]),
+        ]);
+    }
+
+    #[test]
+    fn not_of_or_applies_de_morgan() {
+        // not (e10s or stylo) == (not e10s) and (not stylo)
+        let result = condition_to_tokensets("not (e10s or stylo)");
+        assert_eq!(result, vec![tokenset(vec!["not e10s", "not stylo"])]);
+    }
+
+    #[test]
+    fn nested_parens_group_correctly() {
+        let result = condition_to_tokensets("((os == \"win\") and e10s) or stylo");
+        assert_eq!(result, vec![
+            tokenset(vec!["(os == \"win\")", "e10s"]),
+            tokenset(vec!["stylo"]),
+        ]);
+    }
+
+    #[test]
+    fn not_equal_comparison_is_a_single_literal() {
+        let result = condition_to_tokensets("(os != \"win\")");
+        assert_eq!(result, vec![tokenset(vec!["(os != \"win\")"])]);
+    }
+}
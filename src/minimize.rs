@@ -0,0 +1,292 @@
+// A Quine-McCluskey style boolean minimizer for tokensets. Each tokenset is
+// a product term (an AND of literals, where a literal is a token or its
+// `flip()`); the tokensets together form a sum-of-products (a disjunction of
+// those terms) that this module reduces to a minimal equivalent cover.
+//
+// This replaces the old `try_collapse_flip`/`try_collapse2` special cases:
+// `try_collapse_flip` already performed the QM "combine" step for a single
+// pair of terms that differ in exactly one literal; here we run that step to
+// a fixed point across every pair, track which terms never combine further
+// (the prime implicants), and then select a minimal cover of the original
+// terms from those primes via essential-prime extraction followed by a
+// greedy cover of whatever is left.
+//
+// `CollapseRule`s are no longer required for the combining itself, but they
+// are still useful as don't-care hints: a rule's `alternatives` name a
+// group of mutually exclusive conditions (e.g. the various Windows
+// versions) that can be dropped entirely once every member of the group is
+// present across otherwise-identical terms. We apply that rule-driven
+// reduction before minimization, and let the rule-free QM pass handle
+// everything else.
+
+use std::collections::BTreeMap;
+
+use condition::flip;
+use rules::CollapseRule;
+
+fn match_prereqs(rule: &CollapseRule, tokenset: &[String]) -> bool {
+    rule.prerequisites.iter().all(|prereq| tokenset.contains(prereq))
+}
+
+// Repeatedly looks for a rule whose alternatives are each present exactly
+// once among a group of tokensets that are otherwise identical (and that
+// satisfy the rule's prerequisites), and collapses that group down to a
+// single tokeet with the alternative removed. This is the same 1:1-mapping
+// and all-else-identical requirement the old `try_collapse`/`try_collapse2`
+// enforced, generalized to alternatives lists of any length.
+fn reduce_with_rule_hints(mut tokensets: Vec<Vec<String>>, rules: &Vec<CollapseRule>) -> Vec<Vec<String>> {
+    loop {
+        let mut collapsed_any = false;
+        'rules: for rule in rules {
+            if rule.alternatives.is_empty() {
+                continue;
+            }
+
+            // Group the tokensets that could participate in this rule by
+            // their "signature" - every literal except whichever
+            // alternative they hold - so that a group only forms among
+            // tokensets that are identical other than the alternative.
+            let mut groups: BTreeMap<Vec<String>, BTreeMap<String, usize>> = BTreeMap::new();
+            for (idx, tokenset) in tokensets.iter().enumerate() {
+                if !match_prereqs(rule, tokenset) {
+                    continue;
+                }
+                let present: Vec<&String> = rule.alternatives.iter()
+                    .filter(|alt| tokenset.contains(alt))
+                    .collect();
+                if present.len() != 1 {
+                    continue;
+                }
+                let alt = present[0].clone();
+                let mut signature: Vec<String> = tokenset.iter()
+                    .filter(|t| *t != &alt)
+                    .cloned()
+                    .collect();
+                signature.sort();
+                groups.entry(signature).or_default().insert(alt, idx);
+            }
+
+            for (signature, alt_to_index) in groups {
+                if alt_to_index.len() != rule.alternatives.len() {
+                    continue;
+                }
+                let mut indices: Vec<usize> = alt_to_index.values().cloned().collect();
+                indices.sort();
+                debug!("Collapsed {:?} to {:?} via {:?}",
+                       indices.iter().map(|&i| &tokensets[i]).collect::<Vec<_>>(), signature, rule);
+                tokensets[indices[0]] = signature;
+                for &remove_idx in indices[1..].iter().rev() {
+                    tokensets.remove(remove_idx);
+                }
+                collapsed_any = true;
+                continue 'rules;
+            }
+        }
+        if !collapsed_any {
+            break;
+        }
+    }
+    tokensets
+}
+
+// If `a` and `b` differ in exactly one literal - one has a token, the other
+// has `flip()` of that token, everything else is identical - returns the
+// merged term with that literal removed. This is the QM "combine" step.
+fn try_merge(a: &[String], b: &[String]) -> Option<Vec<String>> {
+    if a.len() != b.len() {
+        return None;
+    }
+    let mut remaining_b: Vec<&String> = b.iter().collect();
+    let mut result = Vec::new();
+    let mut already_merged = false;
+    for tok in a {
+        if let Some(pos) = remaining_b.iter().position(|t| *t == tok) {
+            remaining_b.remove(pos);
+            result.push(tok.clone());
+        } else if !already_merged {
+            let flipped = flip(tok);
+            match remaining_b.iter().position(|t| **t == flipped) {
+                Some(pos) => {
+                    remaining_b.remove(pos);
+                    already_merged = true;
+                }
+                None => return None,
+            }
+        } else {
+            return None;
+        }
+    }
+    if already_merged && remaining_b.is_empty() { Some(result) } else { None }
+}
+
+// `prime` covers `term` iff every literal of `prime` is present in `term`
+// (i.e. anything satisfying `term` also satisfies the broader condition
+// `prime` describes).
+fn covers(prime: &[String], term: &[String]) -> bool {
+    prime.iter().all(|literal| term.contains(literal))
+}
+
+// Combines `terms` pairwise to a fixed point, collecting every term that
+// never combines further into the set of prime implicants.
+fn prime_implicants(terms: Vec<Vec<String>>) -> Vec<Vec<String>> {
+    let mut current = terms;
+    let mut primes: Vec<Vec<String>> = Vec::new();
+    loop {
+        let mut used = vec![false; current.len()];
+        let mut next: Vec<Vec<String>> = Vec::new();
+        for i in 0..current.len() {
+            for j in (i + 1)..current.len() {
+                if let Some(merged) = try_merge(&current[i], &current[j]) {
+                    used[i] = true;
+                    used[j] = true;
+                    if !next.contains(&merged) {
+                        next.push(merged);
+                    }
+                }
+            }
+        }
+        for (idx, term) in current.iter().enumerate() {
+            if !used[idx] && !primes.contains(term) {
+                primes.push(term.clone());
+            }
+        }
+        if next.is_empty() {
+            break;
+        }
+        current = next;
+    }
+    primes
+}
+
+// Builds the prime-implicant chart (primes x original terms) and selects a
+// minimal cover: first take every prime that is the *only* one covering some
+// term (the essential primes), then greedily pick whichever remaining prime
+// covers the most still-uncovered terms until everything is covered.
+fn select_cover(primes: Vec<Vec<String>>, terms: &[Vec<String>]) -> Vec<Vec<String>> {
+    let mut uncovered: Vec<usize> = (0..terms.len()).collect();
+    let mut selected: Vec<usize> = Vec::new();
+
+    loop {
+        let mut essential = None;
+        for &col in &uncovered {
+            let coverers: Vec<usize> = (0..primes.len())
+                .filter(|&p| covers(&primes[p], &terms[col]))
+                .collect();
+            if coverers.len() == 1 && !selected.contains(&coverers[0]) {
+                essential = Some(coverers[0]);
+                break;
+            }
+        }
+        match essential {
+            Some(p) => {
+                selected.push(p);
+                uncovered.retain(|&col| !covers(&primes[p], &terms[col]));
+            }
+            None => break,
+        }
+    }
+
+    while !uncovered.is_empty() {
+        let best = (0..primes.len())
+            .filter(|p| !selected.contains(p))
+            .max_by_key(|&p| uncovered.iter().filter(|&&col| covers(&primes[p], &terms[col])).count());
+        match best {
+            Some(p) => {
+                selected.push(p);
+                uncovered.retain(|&col| !covers(&primes[p], &terms[col]));
+            }
+            None => break,
+        }
+    }
+
+    selected.into_iter().map(|p| primes[p].clone()).collect()
+}
+
+fn quine_mccluskey(terms: Vec<Vec<String>>) -> Vec<Vec<String>> {
+    let primes = prime_implicants(terms.clone());
+    select_cover(primes, &terms)
+}
+
+// Minimizes `tokensets` in place: applies rule-driven don't-care reductions
+// and Quine-McCluskey minimization until nothing changes any more.
+pub fn collapse(tokensets: &mut Vec<Vec<String>>, rules: &Vec<CollapseRule>) {
+    let mut current = tokensets.clone();
+    loop {
+        let reduced = reduce_with_rule_hints(current.clone(), rules);
+        let minimized = quine_mccluskey(reduced);
+        if minimized == current {
+            break;
+        }
+        current = minimized;
+    }
+    *tokensets = current;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rules;
+
+    fn tokenset(literals: Vec<&str>) -> Vec<String> {
+        literals.into_iter().map(String::from).collect()
+    }
+
+    // The worked example from the doc comment on `build_collapse_rules`:
+    // Windows 7 drops its redundant `not webrender` via a prerequisite
+    // rule, then the two Windows versions collapse into each other via the
+    // alternatives rule, leaving a single tokenset. Uses just the two rules
+    // the doc comment walks through, rather than the full built-in set,
+    // since several of the other built-in rules would also fire here.
+    #[test]
+    fn win7_win10_example_collapses_to_one_tokenset() {
+        let mut tokensets = vec![
+            tokenset(vec!["(os == \"win\")", "(version == \"6.1.7601\")", "not webrender", "e10s"]),
+            tokenset(vec!["(os == \"win\")", "(version == \"10.0.15063\")", "e10s"]),
+        ];
+        let rules = vec![
+            CollapseRule::new(vec![
+                "(os == \"win\")",
+                "(version == \"6.1.7601\")",
+            ], vec![
+                "not webrender",
+            ]),
+            CollapseRule::new(vec![
+                "(os == \"win\")",
+            ], vec![
+                "(version == \"6.1.7601\")",
+                "(version == \"10.0.15063\")",
+            ]),
+        ];
+        collapse(&mut tokensets, &rules);
+        assert_eq!(tokensets, vec![tokenset(vec!["(os == \"win\")", "e10s"])]);
+    }
+
+    // A prime-implicant chart with no essential primes at all: each of the
+    // three terms is covered by exactly two of the three single-literal
+    // primes, so the essential-prime pass selects nothing and the greedy
+    // loop has to pick a minimal cover on its own.
+    #[test]
+    fn select_cover_falls_back_to_greedy_when_nothing_is_essential() {
+        let primes = vec![tokenset(vec!["a"]), tokenset(vec!["b"]), tokenset(vec!["c"])];
+        let terms = vec![
+            tokenset(vec!["a", "b"]),
+            tokenset(vec!["b", "c"]),
+            tokenset(vec!["a", "c"]),
+        ];
+        let result = select_cover(primes, &terms);
+        assert_eq!(result, vec![tokenset(vec!["c"]), tokenset(vec!["b"])]);
+    }
+
+    #[test]
+    fn collapse_is_idempotent() {
+        let rules = rules::build_collapse_rules();
+        let mut tokensets = vec![
+            tokenset(vec!["(os == \"mac\")", "e10s"]),
+            tokenset(vec!["(os == \"mac\")", "not e10s"]),
+        ];
+        collapse(&mut tokensets, &rules);
+        let once_collapsed = tokensets.clone();
+        collapse(&mut tokensets, &rules);
+        assert_eq!(tokensets, once_collapsed);
+    }
+}
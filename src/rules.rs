@@ -0,0 +1,527 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+
+#[derive(Debug)]
+pub struct CollapseRule {
+    pub prerequisites: Vec<String>,
+    pub alternatives: Vec<String>,
+}
+
+impl CollapseRule {
+    pub fn new(prerequisites: Vec<&str>, alternatives: Vec<&str>) -> Self {
+        CollapseRule {
+            prerequisites: prerequisites.into_iter().map(String::from).collect(),
+            alternatives: alternatives.into_iter().map(String::from).collect(),
+        }
+    }
+}
+
+// The built-in set of rules to use when collapsing, in the absence of a
+// user-supplied rules file. Each rule has two vectors: the first is a vector
+// of "prerequisites" and the second is a vector of "alternatives". In order
+// to collapse a group of tokensets via a rule, the following conditions must
+// be satisfied:
+// 1) all the tokensets in the group must match all the "prerequisite"
+//    conditions,
+// 2) there must be a 1:1 mapping between the "alternative" conditions and the
+//    tokensets - that is, each tokenset must contain exactly one of the
+//    "alternative" conditions.
+// 3) other than the "alternative" condition, all the conditions in the tokenset
+//    must be the same across all tokensets in the group.
+// The collapsing happens by removing the alternative conditions from the
+// tokensets in the group, which will make them identical, and then dropping
+// all of the tokensets except for one.
+//
+// The rules are applied over and over until nothing changes any more.
+//
+// As a concrete example consider this ruleset taken from an .ini file:
+//   expected:
+//     if (os == "win") and (version == "6.1.7601") and not webrender and e10s: FAIL
+//     if (os == "win") and (version == "10.0.15063") and e10s: FAIL
+//     PASS
+// This will get converted to two tokensets:
+//   { '(os == "win")', '(version == "6.1.7601")', 'not webrender', 'e10s' }
+//   { '(os == "win")', '(version == "10.0.15063")', 'e10s' }
+// Then we take this rule:
+//   CollapseRule::new(vec![
+//       "(os == \"win\")",
+//       "(version == \"6.1.7601\")",
+//   ], vec![
+//       "not webrender",
+//   ]),
+// which basically says in english "if windows 7, then the not-webrender clause
+// is redundant". We check the three rules listed above against the "group"
+// consisting of just the first tokenset:
+// 1) The windows and version checks, which are the prerequisites for the rule,
+//    are present in the tokenset. check.
+// 2) There is one rule in the tokenset, and it has the one alternative
+//    condition. So this is trivially a 1:1 mapping. check.
+// 3) There is only one tokenset in the group so this is trivially true. check.
+// So we apply the collapsing indicated by the rule, which collapses the group
+// into a single rule without any of the alternative conditions.
+//
+// This reduces our tokensets to this:
+//   { '(os == "win")', '(version == "6.1.7601")', 'e10s' }
+//   { '(os == "win")', '(version == "10.0.15063")', 'e10s' }
+// Now we can use this rule:
+//   CollapseRule::new(vec![
+//       "(os == \"win\")",
+//   ], vec![
+//       "(version == \"6.1.7601\")",
+//       "(version == \"10.0.15063\")",
+//   ]),
+// to further collapse things. We make a group of the two tokensets and check
+// the conditions:
+// 1) Both tokensets in the group have the windows condition. check.
+// 2) There is exactly one tokenset in the group with each of the version
+//    conditions from the alternatives list. check.
+// 3) All the other conditions in the tokenset (basically the e10s one) are
+//    identical across the group. check.
+// So we collapse by dropping the version conditions and get this:
+//   { '(os == "win")', 'e10s' }
+// which translates back into:
+//   expected:
+//     if (os == "win") and e10s: FAIL
+//     PASS
+// and that's a minimal expression of the original thing, given the ruleset
+// we applied.
+pub fn build_collapse_rules() -> Vec<CollapseRule> {
+    vec![
+        // MacOS rules
+        CollapseRule::new(vec![
+            "(os == \"mac\")",
+        ], vec![
+            "(version == \"OS X 10.10.5\")",
+        ]),
+        CollapseRule::new(vec![
+            "(os == \"mac\")",
+        ], vec![
+            "e10s",
+        ]),
+        CollapseRule::new(vec![
+            "(os == \"mac\")",
+        ], vec![
+            "not webrender",
+        ]),
+        CollapseRule::new(vec![
+            "(os == \"mac\")",
+        ], vec![
+            "(processor == \"x86_64\")",
+        ]),
+        CollapseRule::new(vec![
+            "(os == \"mac\")",
+        ], vec![
+            "(bits == 64)",
+        ]),
+
+        // Win32 rules
+        CollapseRule::new(vec![
+            "(os == \"win\")",
+            "(version == \"6.1.7601\")",
+        ], vec![
+            "e10s",
+        ]),
+        CollapseRule::new(vec![
+            "(os == \"win\")",
+            "(version == \"6.1.7601\")",
+        ], vec![
+            "not webrender",
+        ]),
+        CollapseRule::new(vec![
+            "(os == \"win\")",
+            "(version == \"6.1.7601\")",
+        ], vec![
+            "(processor == \"x86\")",
+        ]),
+        CollapseRule::new(vec![
+            "(os == \"win\")",
+            "(version == \"6.1.7601\")",
+        ], vec![
+            "(bits == 32)",
+        ]),
+
+        // Win64 rules
+        CollapseRule::new(vec![
+            "(os == \"win\")",
+            "(version == \"10.0.15063\")",
+        ], vec![
+            "e10s",
+        ]),
+        CollapseRule::new(vec![
+            "(os == \"win\")",
+            "(version == \"10.0.15063\")",
+        ], vec![
+            "(processor == \"x86_64\")",
+        ]),
+        CollapseRule::new(vec![
+            "(os == \"win\")",
+            "(version == \"10.0.15063\")",
+        ], vec![
+            "(bits == 64)",
+        ]),
+
+        // Win WebRender implies win10
+        CollapseRule::new(vec![
+            "(os == \"win\")",
+            "webrender",
+        ], vec![
+            "(version == \"10.0.15063\")",
+        ]),
+
+        // Win version collapsing
+        CollapseRule::new(vec![
+            "(os == \"win\")",
+        ], vec![
+            "(version == \"6.1.7601\")",
+            "(version == \"10.0.15063\")",
+        ]),
+
+        // Linux rules
+        CollapseRule::new(vec![
+            "(os == \"linux\")",
+        ], vec![
+            "(version == \"Ubuntu 16.04\")",
+        ]),
+        CollapseRule::new(vec![
+            "(os == \"linux\")",
+            "(processor == \"x86_64\")",
+        ], vec![
+            "(bits == 64)",
+        ]),
+        CollapseRule::new(vec![
+            "(os == \"linux\")",
+            "(processor == \"x86\")",
+        ], vec![
+            "(bits == 32)",
+        ]),
+        CollapseRule::new(vec![
+            "(os == \"linux\")",
+            "(processor == \"x86\")",
+        ], vec![
+            "not webrender",
+        ]),
+        CollapseRule::new(vec![
+            "(os == \"linux\")",
+        ], vec![
+            "(processor == \"x86_64\")",
+            "(processor == \"x86\")",
+        ]),
+
+        // Linux WebRender implies 64-bit and e10s
+        CollapseRule::new(vec![
+            "(os == \"linux\")",
+            "webrender",
+        ], vec![
+            "(processor == \"x86_64\")",
+        ]),
+        CollapseRule::new(vec![
+            "(os == \"linux\")",
+            "webrender",
+        ], vec![
+            "e10s",
+        ]),
+
+        // Android means no webrender, no e10s
+        CollapseRule::new(vec![
+            "(os == \"android\")",
+        ], vec![
+            "not webrender",
+        ]),
+        CollapseRule::new(vec![
+            "(os == \"android\")",
+        ], vec![
+            "not e10s",
+        ]),
+    ]
+}
+
+// Loads a set of collapse rules from a rules file, falling back to the
+// built-in Mozilla-specific rules when no path is given. This is what keeps
+// the collapser itself domain-agnostic: the engine only knows about
+// CollapseRule, and all the condition-vocabulary knowledge lives in the rules
+// file (or, for backwards compatibility, in build_collapse_rules() above).
+pub fn load_rules(path: Option<&str>) -> Vec<CollapseRule> {
+    match path {
+        Some(path) => read_rules_file(path),
+        None => build_collapse_rules(),
+    }
+}
+
+// Parses a macro definition line of the form:
+//   win_versions = [ "(version == \"6.1.7601\")", "(version == \"10.0.15063\")" ]
+// into its name and the list of quoted strings on the right-hand side.
+// Returns None for any line that isn't a macro definition.
+fn parse_macro_def(line: &str) -> Option<(String, Vec<String>)> {
+    let eq_pos = line.find('=')?;
+    let name = line[..eq_pos].trim();
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return None;
+    }
+    let rest = line[eq_pos + 1..].trim();
+    if !rest.starts_with('[') || !rest.ends_with(']') {
+        return None;
+    }
+    Some((name.to_string(), parse_string_list(&rest[1..rest.len() - 1])))
+}
+
+// Extracts every `"..."` quoted string from `s`, unescaping `\"` and `\\`,
+// ignoring the commas and whitespace between them.
+fn parse_string_list(s: &str) -> Vec<String> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] != '"' {
+            i += 1;
+            continue;
+        }
+        i += 1;
+        let mut value = String::new();
+        while i < chars.len() && chars[i] != '"' {
+            if chars[i] == '\\' && i + 1 < chars.len() {
+                value.push(chars[i + 1]);
+                i += 2;
+            } else {
+                value.push(chars[i]);
+                i += 1;
+            }
+        }
+        i += 1; // consume the closing quote
+        result.push(value);
+    }
+    result
+}
+
+// Expands a single prerequisites/alternatives line into one or more
+// condition strings: a `$name` reference pulls in the whole named macro,
+// anything else is a literal condition.
+fn expand_item(item: &str, macros: &BTreeMap<String, Vec<String>>) -> Vec<String> {
+    match item.chars().next() {
+        Some('$') => {
+            let name = &item[1..];
+            macros.get(name)
+                .unwrap_or_else(|| panic!("Unknown token-group macro ${}", name))
+                .clone()
+        }
+        _ => vec![item.to_string()],
+    }
+}
+
+// Parses a rules file made up of stanzas like:
+//   prerequisites:
+//       (os == "win")
+//       (version == "6.1.7601")
+//   alternatives:
+//       e10s
+//
+//   prerequisites:
+//       (os == "mac")
+//   alternatives:
+//       (version == "OS X 10.10.5")
+// Stanzas are separated by one or more blank lines. Within a stanza, a line
+// of "prerequisites:" or "alternatives:" switches which list subsequent
+// non-blank lines are appended to, until the next such line or the end of
+// the stanza.
+//
+// A line anywhere in the file of the form `name = [ "...", "..." ]` defines
+// a named macro instead of belonging to any stanza; a prerequisites or
+// alternatives line consisting of `$name` expands to that macro's full list
+// of conditions, so a group of related alternatives (e.g. every Windows
+// version) only needs to be spelled out once.
+fn read_rules_file(path: &str) -> Vec<CollapseRule> {
+    let file = File::open(path).unwrap_or_else(|e| panic!("Unable to open rules file {}: {}", path, e));
+    let reader = BufReader::new(file);
+    let lines: Vec<String> = reader.lines().map(|l| l.unwrap()).collect();
+
+    let mut macros: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for line in &lines {
+        if let Some((name, values)) = parse_macro_def(line.trim()) {
+            macros.insert(name, values);
+        }
+    }
+
+    let mut rules = Vec::new();
+    let mut prerequisites: Vec<String> = Vec::new();
+    let mut alternatives: Vec<String> = Vec::new();
+    let mut in_alternatives = false;
+
+    let flush = |prerequisites: &mut Vec<String>, alternatives: &mut Vec<String>, in_alternatives: &mut bool, rules: &mut Vec<CollapseRule>| {
+        if !prerequisites.is_empty() || !alternatives.is_empty() {
+            rules.push(CollapseRule {
+                prerequisites: std::mem::take(prerequisites),
+                alternatives: std::mem::take(alternatives),
+            });
+        }
+        *in_alternatives = false;
+    };
+
+    for line in &lines {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            flush(&mut prerequisites, &mut alternatives, &mut in_alternatives, &mut rules);
+            continue;
+        }
+        if parse_macro_def(trimmed).is_some() {
+            continue;
+        }
+        if trimmed == "prerequisites:" {
+            in_alternatives = false;
+            continue;
+        }
+        if trimmed == "alternatives:" {
+            in_alternatives = true;
+            continue;
+        }
+        let expanded = expand_item(trimmed, &macros);
+        if in_alternatives {
+            alternatives.extend(expanded);
+        } else {
+            prerequisites.extend(expanded);
+        }
+    }
+    flush(&mut prerequisites, &mut alternatives, &mut in_alternatives, &mut rules);
+
+    rules
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    // Writes `contents` to a fresh temp file and returns its path, so each
+    // test gets its own file without clobbering another test's.
+    fn write_temp_rules_file(name: &str, contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("supercollapser_test_{}.rules", name));
+        let mut file = File::create(&path).unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        path
+    }
+
+    #[test]
+    fn read_rules_file_parses_stanzas_separated_by_blank_lines() {
+        let path = write_temp_rules_file("stanzas", r#"
+prerequisites:
+    (os == "win")
+    (version == "6.1.7601")
+alternatives:
+    e10s
+
+prerequisites:
+    (os == "mac")
+alternatives:
+    (version == "OS X 10.10.5")
+"#);
+        let rules = read_rules_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].prerequisites, vec!["(os == \"win\")", "(version == \"6.1.7601\")"]);
+        assert_eq!(rules[0].alternatives, vec!["e10s"]);
+        assert_eq!(rules[1].prerequisites, vec!["(os == \"mac\")"]);
+        assert_eq!(rules[1].alternatives, vec!["(version == \"OS X 10.10.5\")"]);
+    }
+
+    #[test]
+    fn read_rules_file_allows_prerequisites_only_stanza() {
+        let path = write_temp_rules_file("prereqs-only", r#"
+prerequisites:
+    (os == "linux")
+"#);
+        let rules = read_rules_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].prerequisites, vec!["(os == \"linux\")"]);
+        assert!(rules[0].alternatives.is_empty());
+    }
+
+    #[test]
+    fn parse_macro_def_reads_a_named_token_group() {
+        let result = parse_macro_def(r#"win_versions = [ "(version == \"6.1.7601\")", "(version == \"10.0.15063\")" ]"#);
+        assert_eq!(result, Some((
+            "win_versions".to_string(),
+            vec!["(version == \"6.1.7601\")".to_string(), "(version == \"10.0.15063\")".to_string()],
+        )));
+    }
+
+    #[test]
+    fn parse_macro_def_rejects_a_missing_closing_bracket() {
+        // Malformed: no closing `]`. This isn't a macro def, so the line
+        // falls through and is treated as an ordinary condition line.
+        let result = parse_macro_def(r#"win_versions = [ "(version == \"6.1.7601\")""#);
+        assert_eq!(result, None);
+    }
+
+    #[test]
+    fn parse_string_list_unescapes_quotes_and_backslashes() {
+        let result = parse_string_list(r#""a\"b", "c\\d""#);
+        assert_eq!(result, vec!["a\"b".to_string(), "c\\d".to_string()]);
+    }
+
+    #[test]
+    fn expand_item_passes_through_a_literal_condition() {
+        let macros = BTreeMap::new();
+        assert_eq!(expand_item("e10s", &macros), vec!["e10s".to_string()]);
+    }
+
+    #[test]
+    fn expand_item_expands_a_macro_reference() {
+        let mut macros = BTreeMap::new();
+        macros.insert("win_versions".to_string(), vec![
+            "(version == \"6.1.7601\")".to_string(),
+            "(version == \"10.0.15063\")".to_string(),
+        ]);
+        assert_eq!(expand_item("$win_versions", &macros), vec![
+            "(version == \"6.1.7601\")".to_string(),
+            "(version == \"10.0.15063\")".to_string(),
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown token-group macro $win_versions")]
+    fn expand_item_panics_on_unknown_macro() {
+        let macros = BTreeMap::new();
+        expand_item("$win_versions", &macros);
+    }
+
+    #[test]
+    fn read_rules_file_expands_a_macro_in_an_alternatives_list() {
+        let path = write_temp_rules_file("macro-expansion", r#"
+win_versions = [ "(version == \"6.1.7601\")", "(version == \"10.0.15063\")" ]
+
+prerequisites:
+    (os == "win")
+alternatives:
+    $win_versions
+"#);
+        let rules = read_rules_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].prerequisites, vec!["(os == \"win\")"]);
+        assert_eq!(rules[0].alternatives, vec![
+            "(version == \"6.1.7601\")".to_string(),
+            "(version == \"10.0.15063\")".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn read_rules_file_treats_a_stray_equals_as_a_literal_condition() {
+        // `foo = bar` isn't a macro def (no brackets), so it's kept as an
+        // ordinary (if unusual) condition line rather than rejected.
+        let path = write_temp_rules_file("stray-equals", r#"
+prerequisites:
+    foo = bar
+alternatives:
+    e10s
+"#);
+        let rules = read_rules_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].prerequisites, vec!["foo = bar".to_string()]);
+    }
+}